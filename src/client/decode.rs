@@ -0,0 +1,153 @@
+//! Transparent decompression of response bodies.
+//!
+//! The client stacks these adapters over the framed body so that `gzip`/
+//! `deflate` codings are peeled off before the bytes reach the caller. `flate`
+//! only exposes whole-buffer inflation, so each adapter reads its inner reader
+//! to end on first use and then serves the inflated bytes; it still presents a
+//! plain `Reader` over whatever reader it wraps.
+use std::io::{IoResult, MemReader, IoError, InvalidInput};
+
+use flate;
+
+/// A decoder in a stack that can be torn back down to the base reader it wraps.
+///
+/// `B` is the reader at the bottom of the stack — in the client that is the
+/// framing reader, but keeping it generic lets this module stay independent of
+/// the response plumbing.
+pub trait Decoder<B>: Reader {
+    /// Borrow the base reader at the bottom of the stack.
+    fn base(&self) -> &B;
+    /// Consume the stack and return the base reader underneath it.
+    fn into_base(self: Box<Self>) -> B;
+}
+
+/// A `Reader` adapter that transparently inflates a `gzip` stream.
+pub struct GzDecoder<B> {
+    inner: Box<Decoder<B> + Send>,
+    plain: Option<MemReader>,
+}
+
+impl<B> GzDecoder<B> {
+    pub fn new(inner: Box<Decoder<B> + Send>) -> GzDecoder<B> {
+        GzDecoder { inner: inner, plain: None }
+    }
+}
+
+impl<B> Reader for GzDecoder<B> {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<uint> {
+        if self.plain.is_none() {
+            let raw = try!(self.inner.read_to_end());
+            self.plain = Some(MemReader::new(try!(gunzip(raw.as_slice()))));
+        }
+        self.plain.as_mut().unwrap().read(buf)
+    }
+}
+
+impl<B> Decoder<B> for GzDecoder<B> {
+    fn base(&self) -> &B { self.inner.base() }
+    fn into_base(self: Box<Self>) -> B { self.inner.into_base() }
+}
+
+/// A `Reader` adapter that transparently inflates a `deflate` (zlib) stream.
+pub struct DeflateDecoder<B> {
+    inner: Box<Decoder<B> + Send>,
+    plain: Option<MemReader>,
+}
+
+impl<B> DeflateDecoder<B> {
+    pub fn new(inner: Box<Decoder<B> + Send>) -> DeflateDecoder<B> {
+        DeflateDecoder { inner: inner, plain: None }
+    }
+}
+
+impl<B> Reader for DeflateDecoder<B> {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<uint> {
+        if self.plain.is_none() {
+            let raw = try!(self.inner.read_to_end());
+            let plain = match flate::inflate_bytes_zlib(raw.as_slice()) {
+                Some(bytes) => bytes.as_slice().to_vec(),
+                None => return Err(malformed("malformed deflate stream")),
+            };
+            self.plain = Some(MemReader::new(plain));
+        }
+        self.plain.as_mut().unwrap().read(buf)
+    }
+}
+
+impl<B> Decoder<B> for DeflateDecoder<B> {
+    fn base(&self) -> &B { self.inner.base() }
+    fn into_base(self: Box<Self>) -> B { self.inner.into_base() }
+}
+
+fn malformed(desc: &'static str) -> IoError {
+    IoError { kind: InvalidInput, desc: desc, detail: None }
+}
+
+/// Decode a single gzip member into its plaintext, honouring the header flags.
+fn gunzip(raw: &[u8]) -> IoResult<Vec<u8>> {
+    // ID1 ID2 CM FLG, then MTIME(4) XFL OS — a 10 byte fixed header.
+    if raw.len() < 10 || raw[0] != 0x1f || raw[1] != 0x8b || raw[2] != 8 {
+        return Err(malformed("malformed gzip stream"));
+    }
+    let flg = raw[3];
+    let mut pos = 10u;
+
+    if flg & 0x04 != 0 {
+        // FEXTRA: a two byte length followed by that many bytes.
+        if pos + 2 > raw.len() { return Err(malformed("malformed gzip stream")); }
+        let xlen = raw[pos] as uint | (raw[pos + 1] as uint << 8);
+        pos += 2 + xlen;
+    }
+    if flg & 0x08 != 0 { pos = try!(skip_cstr(raw, pos)); } // FNAME
+    if flg & 0x10 != 0 { pos = try!(skip_cstr(raw, pos)); } // FCOMMENT
+    if flg & 0x02 != 0 { pos += 2; }                        // FHCRC
+
+    // The trailer is an 8 byte CRC32 + ISIZE; the payload in between is DEFLATE.
+    if pos + 8 > raw.len() { return Err(malformed("malformed gzip stream")); }
+    match flate::inflate_bytes(raw.slice(pos, raw.len() - 8)) {
+        Some(bytes) => Ok(bytes.as_slice().to_vec()),
+        None => Err(malformed("malformed gzip stream")),
+    }
+}
+
+/// Advance past a NUL-terminated string starting at `pos`, returning the index
+/// just after the terminator.
+fn skip_cstr(raw: &[u8], pos: uint) -> IoResult<uint> {
+    let mut i = pos;
+    while i < raw.len() {
+        if raw[i] == 0 { return Ok(i + 1); }
+        i += 1;
+    }
+    Err(malformed("unterminated gzip header field"))
+}
+
+#[cfg(test)]
+mod tests {
+    use flate;
+
+    use super::gunzip;
+
+    // Build a gzip member wrapping `data`, exercising the FEXTRA and FNAME
+    // header fields so the flag-skipping in `gunzip` is covered.
+    fn gzip_member(data: &[u8]) -> Vec<u8> {
+        let mut out = vec![0x1f, 0x8b, 0x08, 0x04 | 0x08]; // FEXTRA | FNAME
+        out.push_all(&[0, 0, 0, 0, 0, 0xff]);              // MTIME, XFL, OS
+        out.push_all(&[0x02, 0x00, 0xaa, 0xbb]);           // FEXTRA: XLEN=2 + data
+        out.push_all(b"name.txt");                         // FNAME
+        out.push(0);
+        out.push_all(flate::deflate_bytes(data).as_slice());
+        out.push_all(&[0, 0, 0, 0, 0, 0, 0, 0]);           // CRC32 + ISIZE
+        out
+    }
+
+    #[test]
+    fn test_gunzip_roundtrip_with_header_fields() {
+        let member = gzip_member(b"hello, trailers");
+        assert_eq!(gunzip(member.as_slice()).unwrap().as_slice(), b"hello, trailers");
+    }
+
+    #[test]
+    fn test_gunzip_rejects_short_input() {
+        assert!(gunzip(&[0x1f, 0x8b]).is_err());
+    }
+}
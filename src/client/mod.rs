@@ -0,0 +1,6 @@
+//! HTTP Client
+pub use self::response::Response;
+
+pub mod decode;
+pub mod pool;
+pub mod response;
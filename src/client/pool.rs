@@ -0,0 +1,74 @@
+//! Client-side pooling of keep-alive connections.
+use std::collections::HashMap;
+use std::io::BufferedReader;
+
+use net::NetworkStream;
+
+/// A keep-alive connection reclaimed from a fully-consumed `Response`, ready to
+/// be returned to a `ConnectionPool` for reuse.
+pub struct PooledStream {
+    stream: BufferedReader<Box<NetworkStream + Send>>,
+}
+
+impl PooledStream {
+    /// Wrap a reclaimed buffered stream so it can be pooled.
+    pub fn new(stream: BufferedReader<Box<NetworkStream + Send>>) -> PooledStream {
+        PooledStream { stream: stream }
+    }
+
+    /// Take the buffered stream back out to issue the next request on it.
+    pub fn into_inner(self) -> BufferedReader<Box<NetworkStream + Send>> {
+        self.stream
+    }
+}
+
+/// A pool of idle keep-alive connections, keyed by origin host so a request
+/// reuses a socket already open to the same server.
+pub struct ConnectionPool {
+    idle: HashMap<String, Vec<PooledStream>>,
+}
+
+impl ConnectionPool {
+    /// Create an empty pool.
+    pub fn new() -> ConnectionPool {
+        ConnectionPool { idle: HashMap::new() }
+    }
+
+    /// Return a reclaimed connection to the pool under its origin `host`.
+    pub fn put(&mut self, host: String, conn: PooledStream) {
+        self.idle.entry(host).or_insert_with(Vec::new).push(conn);
+    }
+
+    /// Take an idle connection previously opened to `host`, if one is pooled.
+    pub fn take(&mut self, host: &str) -> Option<PooledStream> {
+        match self.idle.get_mut(host) {
+            Some(conns) => conns.pop(),
+            None => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::BufferedReader;
+
+    use mock::MockStream;
+    use net::NetworkStream;
+
+    use super::{ConnectionPool, PooledStream};
+
+    fn pooled() -> PooledStream {
+        PooledStream::new(BufferedReader::new(box MockStream::new() as Box<NetworkStream + Send>))
+    }
+
+    #[test]
+    fn test_pool_keyed_by_host() {
+        let mut pool = ConnectionPool::new();
+        pool.put("example.com".to_string(), pooled());
+
+        assert!(pool.take("other.com").is_none());
+        assert!(pool.take("example.com").is_some());
+        // Only one was pooled for this host.
+        assert!(pool.take("example.com").is_none());
+    }
+}
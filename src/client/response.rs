@@ -1,14 +1,21 @@
 //! Client Responses
 use std::num::FromPrimitive;
-use std::io::{BufferedReader, IoResult};
+use std::collections::HashSet;
+use std::io::{BufferedReader, IoResult, EndOfFile};
+
+use url::Url;
 
 use header;
-use header::common::{ContentLength, TransferEncoding};
+use header::common::Location;
+use header::common::{Connection, ContentLength, ContentEncoding, TransferEncoding};
+use header::common::connection::Close;
 use header::common::transfer_encoding::Chunked;
 use net::{NetworkStream, HttpStream};
 use http::{read_status_line, HttpReader, SizedReader, ChunkedReader, EofReader, RawStatus};
 use status;
 use version;
+use super::decode::{Decoder, GzDecoder, DeflateDecoder};
+use super::pool::PooledStream;
 use {HttpResult, HttpStatusError};
 
 /// A response for a client request to a remote server.
@@ -20,13 +27,27 @@ pub struct Response<S = HttpStream> {
     /// The HTTP version of this response from the server.
     pub version: version::HttpVersion,
     status_raw: RawStatus,
-    body: HttpReader<BufferedReader<Box<NetworkStream + Send>>>,
+    body: Body,
 }
 
 impl Response {
 
-    /// Creates a new response from a server.
+    /// Creates a new response from a server, yielding the de-framed body with
+    /// any compression codings left in place.
     pub fn new(stream: Box<NetworkStream + Send>) -> HttpResult<Response> {
+        Response::construct(stream, false)
+    }
+
+    /// Like `new`, but transparently unwraps any `gzip`/`deflate` transfer- or
+    /// content-codings so the `Reader` impl yields plaintext.
+    ///
+    /// Decoding is opt-in only through the choice of constructor: callers that
+    /// want the raw bytes use `new`. No client-level setting picks between them.
+    pub fn with_decoding(stream: Box<NetworkStream + Send>) -> HttpResult<Response> {
+        Response::construct(stream, true)
+    }
+
+    fn construct(stream: Box<NetworkStream + Send>, decode: bool) -> HttpResult<Response> {
         let mut stream = BufferedReader::new(stream);
         let (version, raw_status) = try!(read_status_line(&mut stream));
         let status = match FromPrimitive::from_u16(raw_status.0) {
@@ -35,20 +56,26 @@ impl Response {
         };
         debug!("{} {}", version, status);
 
-        let headers = try!(header::Headers::from_raw(&mut stream));
+        let mut headers = try!(header::Headers::from_raw(&mut stream));
         debug!("{}", headers);
 
-        let body = if headers.has::<TransferEncoding>() {
+        // Compression codings wrapped around the message body. `Chunked` is
+        // framing and is resolved below by picking the matching `HttpReader`;
+        // the rest are decompressions, normalized to a single `Coding`. The
+        // transfer-codings are the outer layer on the wire and the
+        // content-codings the inner, and within each the last-applied coding is
+        // outermost — so both sublists are peeled in reverse.
+        let mut te_codings = Vec::new();
+        let reader = if headers.has::<TransferEncoding>() {
             match headers.get::<TransferEncoding>() {
-                Some(&TransferEncoding(ref codings)) => {
-                    if codings.len() > 1 {
-                        debug!("TODO: #2 handle other codings: {}", codings);
-                    };
-
-                    if codings.contains(&Chunked) {
+                Some(&TransferEncoding(ref encs)) => {
+                    for enc in encs.iter().filter(|&c| *c != Chunked) {
+                        te_codings.push(Coding::from_transfer(enc));
+                    }
+                    if encs.contains(&Chunked) {
                         ChunkedReader(stream, None)
                     } else {
-                        debug!("not chuncked. read till eof");
+                        debug!("not chunked. read till eof");
                         EofReader(stream)
                     }
                 }
@@ -64,6 +91,39 @@ impl Response {
             EofReader(stream)
         };
 
+        let mut ce_codings = Vec::new();
+        if headers.has::<ContentEncoding>() {
+            match headers.get::<ContentEncoding>() {
+                Some(&ContentEncoding(ref encs)) => {
+                    for enc in encs.iter() {
+                        ce_codings.push(Coding::from_content(enc));
+                    }
+                }
+                None => unreachable!()
+            }
+        }
+
+        // Decode from the wire's outer layer inward: transfer-codings reversed,
+        // then content-codings reversed.
+        let mut codings = Vec::new();
+        codings.extend(te_codings.into_iter().rev());
+        codings.extend(ce_codings.into_iter().rev());
+
+        let framed = FramedReader::new(reader);
+        // Only decode when every coding in the stack is one we handle; a single
+        // unsupported coding (e.g. `br`) means we must hand the body back raw
+        // and keep `Content-Length`, rather than peel some layers and present
+        // still-compressed bytes as plaintext.
+        let decodable = !codings.is_empty() && codings.iter().all(|c| *c != Unsupported);
+        let body = if decode && decodable {
+            // Every coding is handled, so the decoded stream no longer matches
+            // the advertised length.
+            headers.remove::<ContentLength>();
+            Body::Decoded(decode_codings(framed, codings.as_slice()))
+        } else {
+            Body::Raw(framed)
+        };
+
         Ok(Response {
             status: status,
             version: version,
@@ -78,33 +138,333 @@ impl Response {
         &self.status_raw
     }
 
+    /// The trailer headers sent after a `chunked` body, if any.
+    ///
+    /// These are only available once the body `Reader` has been read to EOF,
+    /// since the trailer block follows the terminating `0\r\n` chunk on the
+    /// wire; before that this returns `None`. They are parsed at the framing
+    /// layer, so they remain reachable even when the body was decompressed.
+    pub fn trailers(&self) -> Option<&header::Headers> {
+        self.body.framed().trailers.as_ref()
+    }
+
+    /// The absolute URL this response redirects to, if it is a 3xx carrying a
+    /// usable `Location` header.
+    ///
+    /// The `Location` value must be an absolute URL; a relative one is not
+    /// resolved against the request URL and yields `None`.
+    ///
+    /// `follow_redirects` calls this and re-issues the request to the returned
+    /// URL as-is until it sees a non-redirect
+    /// status, the `RedirectPolicy` hop limit is hit, or a `Location` it has
+    /// already visited reappears (which it turns into an error rather than
+    /// looping forever).
+    ///
+    /// Only the redirect statuses that carry a followable `Location` qualify;
+    /// other 3xx codes such as 304 Not Modified return `None`.
+    pub fn redirect_location(&self) -> Option<Url> {
+        match self.status_raw.0 {
+            301 | 302 | 303 | 307 | 308 => {}
+            _ => return None,
+        }
+        match self.headers.get::<Location>() {
+            Some(&Location(ref loc)) => Url::parse(loc.as_slice()).ok(),
+            None => None,
+        }
+    }
+
+    /// Whether following this redirect should drop the request body and switch
+    /// the method to `GET`, per the usual browser semantics for 301/302/303.
+    pub fn redirect_drops_body(&self) -> bool {
+        match self.status_raw.0 {
+            301 | 302 | 303 => true,
+            _ => false,
+        }
+    }
+
+    /// Reclaims the underlying connection for reuse, if it is safe to do so.
+    ///
+    /// A stream is only returned when the message framing guaranteed the body
+    /// boundary was reached — a `SizedReader` drained to zero or a
+    /// `ChunkedReader` that hit its terminating chunk — and the response did
+    /// not carry `Connection: close`. An `EofReader` body has no such boundary
+    /// and is never reusable, so this yields `None`. The returned `PooledStream`
+    /// can be handed to a `ConnectionPool` keyed by origin host for the next
+    /// request.
+    pub fn into_connection(self) -> Option<PooledStream> {
+        if connection_close(&self.headers) || !self.body.framed().reusable() {
+            return None;
+        }
+        Some(PooledStream::new(self.body.into_framed().inner.unwrap()))
+    }
+
     /// Unwraps the Request to return the NetworkStream underneath.
     pub fn unwrap(self) -> Box<NetworkStream + Send> {
-        self.body.unwrap().unwrap()
+        self.body.into_framed().inner.unwrap().unwrap()
     }
 }
 
 impl Reader for Response {
     #[inline]
     fn read(&mut self, buf: &mut [u8]) -> IoResult<uint> {
-        self.body.read(buf)
+        match self.body {
+            Body::Raw(ref mut reader) => reader.read(buf),
+            Body::Decoded(ref mut decoder) => decoder.read(buf),
+        }
+    }
+}
+
+/// How a client should treat 3xx responses that carry a `Location` header.
+pub enum RedirectPolicy {
+    /// Follow every redirect the server sends.
+    FollowAll,
+    /// Never follow; hand the 3xx `Response` back to the caller untouched.
+    FollowNone,
+    /// Follow redirects up to the given number of hops, then fail to break
+    /// any redirect cycle.
+    FollowIf(uint),
+}
+
+/// The hop ceiling applied to `FollowAll`, so a server emitting an unbounded
+/// chain of distinct URLs still terminates rather than looping forever.
+pub static DEFAULT_REDIRECT_LIMIT: uint = 10;
+
+/// Follow 3xx redirects according to `policy`, re-issuing the request with
+/// `send` for each hop until a non-redirect response comes back.
+///
+/// `send` is called with the URL to fetch and a flag that is `true` when this
+/// hop must drop the request body and switch to `GET` (303, and 301/302 per the
+/// usual browser semantics). The hop count is always capped — `FollowAll` uses
+/// `DEFAULT_REDIRECT_LIMIT` — and every URL visited is tracked so a
+/// self-referential or cyclic `Location` terminates with an error instead of
+/// hanging.
+pub fn follow_redirects(policy: RedirectPolicy,
+                        start: Url,
+                        mut send: |&Url, bool| -> HttpResult<Response>)
+                        -> HttpResult<Response> {
+    let max = match policy {
+        FollowNone => 0,
+        FollowAll => DEFAULT_REDIRECT_LIMIT,
+        FollowIf(n) => n,
+    };
+    let mut visited = HashSet::new();
+    visited.insert(start.serialize());
+    let mut url = start;
+    let mut force_get = false;
+    let mut hops = 0u;
+    loop {
+        let res = try!(send(&url, force_get));
+        let next = match res.redirect_location() {
+            Some(next) => next,
+            None => return Ok(res),
+        };
+        match policy {
+            // Hand the redirect response straight back without following.
+            FollowNone => return Ok(res),
+            _ if hops >= max => {
+                debug!("redirect hop limit {} reached, giving up", max);
+                return Err(HttpStatusError);
+            }
+            _ => {}
+        }
+        if !visited.insert(next.serialize()) {
+            debug!("redirect cycle back to {}, aborting", next);
+            return Err(HttpStatusError);
+        }
+        // The next request's method/body follow from the redirect we are acting
+        // on, per `redirect_drops_body`.
+        force_get = res.redirect_drops_body();
+        hops += 1;
+        url = next;
+    }
+}
+
+/// The message body of a `Response`, either as framed on the wire or with the
+/// compression codings peeled off.
+enum Body {
+    /// The body exactly as it arrived, only de-framed by the `HttpReader`.
+    Raw(FramedReader),
+    /// A stack of decompressing adapters layered over the framed body.
+    Decoded(Box<Decoder<FramedReader> + Send>),
+}
+
+impl Body {
+    /// Borrow the framing reader at the base of the body, whatever decoders sit
+    /// on top of it.
+    fn framed(&self) -> &FramedReader {
+        match *self {
+            Body::Raw(ref framed) => framed,
+            Body::Decoded(ref decoder) => decoder.base(),
+        }
+    }
+
+    /// Tear the body down to the framing reader at its base.
+    fn into_framed(self) -> FramedReader {
+        match self {
+            Body::Raw(framed) => framed,
+            Body::Decoded(decoder) => decoder.into_base(),
+        }
+    }
+}
+
+/// The bottom of the body stack: the framed `HttpReader` plus the trailer block
+/// and completion state captured when it reaches EOF. Parsing trailers here
+/// rather than in `Response::read` means they survive even when a decompressor
+/// drains the body with `read_to_end`.
+struct FramedReader {
+    inner: HttpReader<BufferedReader<Box<NetworkStream + Send>>>,
+    trailers: Option<header::Headers>,
+    complete: bool,
+}
+
+impl FramedReader {
+    fn new(inner: HttpReader<BufferedReader<Box<NetworkStream + Send>>>) -> FramedReader {
+        FramedReader { inner: inner, trailers: None, complete: false }
+    }
+
+    /// Whether the connection can be reused: the body must have been read to its
+    /// framed end, and that end must have been definite (`SizedReader`/
+    /// `ChunkedReader`, never `EofReader`).
+    fn reusable(&self) -> bool {
+        self.complete && match self.inner {
+            SizedReader(..) | ChunkedReader(..) => true,
+            EofReader(..) => false,
+        }
+    }
+}
+
+impl Reader for FramedReader {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<uint> {
+        let result = self.inner.read(buf);
+        // A chunked body is terminated by a `0\r\n` chunk that may be followed
+        // by trailer headers; on the first EOF, record completion and parse that
+        // block off the underlying stream.
+        match result {
+            Err(ref e) if e.kind == EndOfFile && !self.complete => {
+                self.complete = true;
+                if let ChunkedReader(ref mut stream, _) = self.inner {
+                    // A body with no trailers still has a `0\r\n\r\n` terminator,
+                    // which parses as an empty header block; keep `trailers`
+                    // `None` in that case so callers can tell "none sent" from
+                    // "present but empty".
+                    self.trailers = match header::Headers::from_raw(stream) {
+                        Ok(h) => if h.iter().next().is_some() { Some(h) } else { None },
+                        Err(_) => None,
+                    };
+                }
+            }
+            _ => {}
+        }
+        result
+    }
+}
+
+/// `FramedReader` is the base of every decoder chain, so it is its own bottom.
+impl Decoder<FramedReader> for FramedReader {
+    fn base(&self) -> &FramedReader { self }
+    fn into_base(self: Box<Self>) -> FramedReader { *self }
+}
+
+/// Whether the response asked for the connection to be closed after the body.
+fn connection_close(headers: &header::Headers) -> bool {
+    match headers.get::<Connection>() {
+        Some(&Connection(ref opts)) => opts.contains(&Close),
+        None => false,
     }
 }
 
+/// A compression coding we can strip off the body, normalized from the
+/// separate `transfer_encoding` and `content_encoding` enums.
+#[deriving(PartialEq, Clone)]
+enum Coding {
+    Gzip,
+    Deflate,
+    /// A coding we do not decode; the body is left as-is.
+    Unsupported,
+}
+
+impl Coding {
+    fn from_transfer(enc: &header::common::transfer_encoding::Encoding) -> Coding {
+        use header::common::transfer_encoding as te;
+        match *enc {
+            te::Gzip => Gzip,
+            te::Deflate => Deflate,
+            ref other => {
+                debug!("unsupported transfer-coding {}, leaving body encoded", other);
+                Unsupported
+            }
+        }
+    }
+
+    fn from_content(enc: &header::common::content_encoding::Encoding) -> Coding {
+        use header::common::content_encoding as ce;
+        match *enc {
+            ce::Gzip => Gzip,
+            ce::Deflate => Deflate,
+            ref other => {
+                debug!("unsupported content-coding {}, leaving body encoded", other);
+                Unsupported
+            }
+        }
+    }
+}
+
+/// Layer the compression `codings` over `framed` so the returned reader yields
+/// fully decoded plaintext. `codings` is ordered outermost-first (the order in
+/// which layers come off the wire), so the first entry wraps `framed` directly
+/// and later entries stack on top. Every coding must be supported; the caller
+/// guarantees this before calling.
+fn decode_codings(framed: FramedReader, codings: &[Coding])
+                  -> Box<Decoder<FramedReader> + Send> {
+    let mut decoder = box framed as Box<Decoder<FramedReader> + Send>;
+    for coding in codings.iter() {
+        decoder = match *coding {
+            Gzip => box GzDecoder::new(decoder) as Box<Decoder<FramedReader> + Send>,
+            Deflate => box DeflateDecoder::new(decoder) as Box<Decoder<FramedReader> + Send>,
+            Unsupported => unreachable!("construct only decodes when every coding is supported"),
+        };
+    }
+    decoder
+}
+
 #[cfg(test)]
 mod tests {
     use std::boxed::BoxAny;
     use std::io::BufferedReader;
 
+    use url::Url;
+
     use header::Headers;
-    use http::{EofReader, RawStatus};
+    use header::common::Location;
+    use http::{ChunkedReader, EofReader, SizedReader, RawStatus};
     use mock::MockStream;
     use net::NetworkStream;
     use status;
     use version;
 
-    use super::Response;
+    use super::{Body, FramedReader, Response, follow_redirects, FollowAll};
+    use super::super::pool::PooledStream;
 
+    fn chunked_response(raw: &[u8]) -> Response {
+        Response {
+            status: status::Ok,
+            headers: Headers::new(),
+            version: version::Http11,
+            body: Body::Raw(FramedReader::new(ChunkedReader(
+                BufferedReader::new(box MockStream::with_input(raw) as Box<NetworkStream + Send>), None))),
+            status_raw: RawStatus(200, "OK".to_string()),
+        }
+    }
+
+    fn drain(res: &mut Response) {
+        let mut buf = [0u8, ..32];
+        loop {
+            match res.read(&mut buf) {
+                Ok(..) => {}
+                Err(..) => break,
+            }
+        }
+    }
 
     #[test]
     fn test_unwrap() {
@@ -112,12 +472,115 @@ mod tests {
             status: status::Ok,
             headers: Headers::new(),
             version: version::Http11,
-            body: EofReader(BufferedReader::new(box MockStream::new() as Box<NetworkStream + Send>)),
-            status_raw: RawStatus(200, "OK".to_string())
+            body: Body::Raw(FramedReader::new(EofReader(BufferedReader::new(box MockStream::new() as Box<NetworkStream + Send>)))),
+            status_raw: RawStatus(200, "OK".to_string()),
         };
 
         let b = res.unwrap().downcast::<MockStream>().unwrap();
         assert_eq!(b, box MockStream::new());
 
     }
+
+    #[test]
+    fn test_trailers_after_chunked_body() {
+        let mut res = chunked_response(b"0\r\nContent-MD5: abc123\r\n\r\n");
+        assert!(res.trailers().is_none());
+        drain(&mut res);
+        let trailers = res.trailers().expect("trailers should be populated at EOF");
+        assert!(trailers.get_raw("Content-MD5").is_some());
+    }
+
+    #[test]
+    fn test_no_trailers_reports_none() {
+        let mut res = chunked_response(b"0\r\n\r\n");
+        drain(&mut res);
+        assert!(res.trailers().is_none());
+    }
+
+    fn response_with(body: Body) -> Response {
+        Response {
+            status: status::Ok,
+            headers: Headers::new(),
+            version: version::Http11,
+            body: body,
+            status_raw: RawStatus(200, "OK".to_string()),
+        }
+    }
+
+    fn empty_framed_sized() -> Body {
+        Body::Raw(FramedReader::new(SizedReader(
+            BufferedReader::new(box MockStream::with_input(b"") as Box<NetworkStream + Send>), 0)))
+    }
+
+    #[test]
+    fn test_into_connection_requires_definite_framing() {
+        // A sized body drained to its end is reusable.
+        let mut sized = response_with(empty_framed_sized());
+        drain(&mut sized);
+        assert!(sized.into_connection().is_some());
+
+        // An EOF-framed body has no definite boundary and is never reusable,
+        // even once read to end.
+        let mut eof = response_with(Body::Raw(FramedReader::new(EofReader(
+            BufferedReader::new(box MockStream::with_input(b"") as Box<NetworkStream + Send>)))));
+        drain(&mut eof);
+        assert!(eof.into_connection().is_none());
+    }
+
+    #[test]
+    fn test_into_connection_none_before_body_drained() {
+        // Completion is tracked explicitly, so an un-read body is not reusable.
+        let sized = response_with(empty_framed_sized());
+        assert!(sized.into_connection().is_none());
+    }
+
+    #[test]
+    fn test_pooled_stream_roundtrips() {
+        let mut sized = response_with(empty_framed_sized());
+        drain(&mut sized);
+        let pooled: PooledStream = sized.into_connection().expect("reusable");
+        let _ = pooled.into_inner();
+    }
+
+    fn redirect_to(code: u16, loc: &str) -> Response {
+        let mut headers = Headers::new();
+        headers.set(Location(loc.to_string()));
+        Response {
+            status: status::Ok,
+            headers: headers,
+            version: version::Http11,
+            body: Body::Raw(FramedReader::new(EofReader(
+                BufferedReader::new(box MockStream::with_input(b"") as Box<NetworkStream + Send>)))),
+            status_raw: RawStatus(code, "Redirect".to_string()),
+        }
+    }
+
+    #[test]
+    fn test_redirect_cycle_terminates_with_error() {
+        let start = Url::parse("http://example.com/loop").unwrap();
+        let mut calls = 0u;
+        let result = follow_redirects(FollowAll, start, |_url, _force_get| {
+            calls += 1;
+            Ok(redirect_to(301, "http://example.com/loop"))
+        });
+        assert!(result.is_err());
+        assert_eq!(calls, 1);
+    }
+
+    #[test]
+    fn test_303_forces_get_on_next_hop() {
+        let start = Url::parse("http://example.com/a").unwrap();
+        let mut seen = Vec::new();
+        let result = follow_redirects(FollowAll, start, |url, force_get| {
+            seen.push(force_get);
+            if url.serialize().as_slice().ends_with("/a") {
+                Ok(redirect_to(303, "http://example.com/b"))
+            } else {
+                Ok(response_with(empty_framed_sized()))
+            }
+        });
+        assert!(result.is_ok());
+        // No forced GET on the initial request; forced after the 303.
+        assert_eq!(seen, vec![false, true]);
+    }
 }